@@ -1,6 +1,8 @@
 //! Hash map types.
 use std::{
+    collections::VecDeque,
     convert::TryFrom,
+    io,
     marker::PhantomData,
     mem,
     ops::{Deref, DerefMut},
@@ -8,15 +10,89 @@ use std::{
 };
 
 use crate::{
-    generated::bpf_map_type::BPF_MAP_TYPE_HASH,
+    generated::bpf_map_type::{
+        BPF_MAP_TYPE_HASH, BPF_MAP_TYPE_LRU_HASH, BPF_MAP_TYPE_LRU_PERCPU_HASH,
+        BPF_MAP_TYPE_PERCPU_HASH,
+    },
     maps::{IterableMap, Map, MapError, MapIter, MapKeys, MapRef, MapRefMut},
-    sys::{bpf_map_delete_elem, bpf_map_lookup_elem, bpf_map_update_elem},
+    sys::{
+        self, bpf_map_delete_batch, bpf_map_delete_elem, bpf_map_lookup_batch, bpf_map_lookup_elem,
+        bpf_map_lookup_elem_per_cpu, bpf_map_update_batch, bpf_map_update_elem,
+        bpf_map_update_elem_per_cpu,
+    },
     Pod,
 };
 
+/// Checks that `map_type` is one of the hash-family map types a hash map wrapper is prepared to
+/// handle, e.g. `BPF_MAP_TYPE_HASH` and `BPF_MAP_TYPE_LRU_HASH` for [`HashMap`].
+fn check_hash_map_type(map_type: u32, allowed: &[u32]) -> Result<(), MapError> {
+    if !allowed.contains(&map_type) {
+        return Err(MapError::InvalidMapType { map_type });
+    }
+    Ok(())
+}
+
+/// Whether `io_error` indicates that the kernel doesn't support the `BPF_MAP_*_BATCH` commands
+/// for the current map, meaning batch operations should fall back to their one-by-one
+/// counterparts.
+fn is_batch_unsupported(io_error: &io::Error) -> bool {
+    io_error.raw_os_error() == Some(libc::EINVAL)
+}
+
+/// Returns the number of possible CPUs, as reported by the kernel. This is the number of value
+/// slots the kernel allocates for each key in a per-CPU map, regardless of how many CPUs are
+/// actually online.
+fn nr_cpus() -> Result<usize, MapError> {
+    sys::nr_cpus().map_err(|(code, io_error)| MapError::SyscallError {
+        call: "sysconf(_SC_NPROCESSORS_CONF)".to_owned(),
+        code,
+        io_error,
+    })
+}
+
+/// A slice of per-CPU values, with one value for each possible CPU.
+///
+/// Per-CPU hash maps (see [`PerCpuHashMap`]) store one separate value for every possible CPU,
+/// so that eBPF programs running on different CPUs can update "their" slot without any
+/// synchronization. `PerCpuValues` is the user-space view of those per-CPU slots.
+pub struct PerCpuValues<V: Pod>(Box<[V]>);
+
+impl<V: Pod> PerCpuValues<V> {
+    pub(crate) fn new(values: Vec<V>) -> PerCpuValues<V> {
+        PerCpuValues(values.into_boxed_slice())
+    }
+}
+
+impl<V: Pod> Deref for PerCpuValues<V> {
+    type Target = [V];
+
+    fn deref(&self) -> &[V] {
+        &self.0
+    }
+}
+
+impl<V: Pod> TryFrom<Vec<V>> for PerCpuValues<V> {
+    type Error = MapError;
+
+    fn try_from(values: Vec<V>) -> Result<PerCpuValues<V>, MapError> {
+        let nr_cpus = nr_cpus()?;
+        if values.len() != nr_cpus {
+            return Err(MapError::InvalidValueSize {
+                size: values.len(),
+                expected: nr_cpus,
+            });
+        }
+        Ok(PerCpuValues::new(values))
+    }
+}
+
 /// A hash map stored inside the kernel, in which both user-space and eBPF programs can insert and
 /// lookup values from.
 ///
+/// `HashMap` wraps both `BPF_MAP_TYPE_HASH` and `BPF_MAP_TYPE_LRU_HASH` maps. LRU maps are a good
+/// fit for bounded caches - e.g. flow or connection tracking tables - since the kernel evicts the
+/// least recently used entries instead of failing inserts once the map is full.
+///
 /// The types of the keys and values must be plain old data (POD), meaning that they
 /// must be safely convertible to and from byte slices.
 ///
@@ -44,11 +120,10 @@ impl<T: Deref<Target = Map>, K: Pod, V: Pod> HashMap<T, K, V> {
         let map_type = map.obj.def.map_type;
 
         // validate the map definition
-        if map_type != BPF_MAP_TYPE_HASH as u32 {
-            return Err(MapError::InvalidMapType {
-                map_type: map_type as u32,
-            })?;
-        }
+        check_hash_map_type(
+            map_type,
+            &[BPF_MAP_TYPE_HASH as u32, BPF_MAP_TYPE_LRU_HASH as u32],
+        )?;
         let size = mem::size_of::<K>();
         let expected = map.obj.def.key_size as usize;
         if size != expected {
@@ -92,6 +167,20 @@ impl<T: Deref<Target = Map>, K: Pod, V: Pod> HashMap<T, K, V> {
     pub unsafe fn keys<'coll>(&'coll self) -> MapKeys<'coll, K, V> {
         MapKeys::new(self)
     }
+
+    /// An iterator visiting all key-value pairs in arbitrary order, fetching up to
+    /// `batch_size` entries per syscall via the kernel's `BPF_MAP_LOOKUP_BATCH` command instead
+    /// of walking the map one key at a time with `BPF_MAP_GET_NEXT_KEY` + `BPF_MAP_LOOKUP_ELEM`.
+    /// The iterator item type is `Result<(K, V), MapError>`, same as [`iter`](HashMap::iter).
+    ///
+    /// Falls back to the one-by-one path used by [`iter`](HashMap::iter) on kernels that
+    /// respond to the batch commands with `EINVAL`.
+    pub unsafe fn iter_batch<'coll>(
+        &'coll self,
+        batch_size: usize,
+    ) -> MapBatchIter<'coll, T, K, V> {
+        MapBatchIter::new(self, batch_size)
+    }
 }
 
 impl<T: DerefMut<Target = Map>, K: Pod, V: Pod> HashMap<T, K, V> {
@@ -119,6 +208,129 @@ impl<T: DerefMut<Target = Map>, K: Pod, V: Pod> HashMap<T, K, V> {
                 io_error,
             })
     }
+
+    /// Inserts many key-value pairs at once via the kernel's `BPF_MAP_UPDATE_BATCH` command.
+    ///
+    /// Falls back to issuing one [`insert`](HashMap::insert) per pair on kernels that respond
+    /// to the batch command with `EINVAL`.
+    pub fn insert_batch(
+        &mut self,
+        entries: impl IntoIterator<Item = (K, V)>,
+        flags: u64,
+    ) -> Result<(), MapError> {
+        let entries: Vec<(K, V)> = entries.into_iter().collect();
+        let fd = self.inner.deref_mut().fd_or_err()?;
+        match bpf_map_update_batch(fd, &entries, flags) {
+            Ok(()) => Ok(()),
+            Err((_, io_error)) if is_batch_unsupported(&io_error) => {
+                for (key, value) in entries {
+                    self.insert(key, value, flags)?;
+                }
+                Ok(())
+            }
+            Err((code, io_error)) => Err(MapError::SyscallError {
+                call: "bpf_map_update_batch".to_owned(),
+                code,
+                io_error,
+            }),
+        }
+    }
+
+    /// Removes many keys at once via the kernel's `BPF_MAP_DELETE_BATCH` command.
+    ///
+    /// Falls back to issuing one [`remove`](HashMap::remove) per key on kernels that respond to
+    /// the batch command with `EINVAL`.
+    pub fn remove_batch(&mut self, keys: impl IntoIterator<Item = K>) -> Result<(), MapError> {
+        let keys: Vec<K> = keys.into_iter().collect();
+        let fd = self.inner.deref_mut().fd_or_err()?;
+        match bpf_map_delete_batch(fd, &keys) {
+            Ok(()) => Ok(()),
+            Err((_, io_error)) if is_batch_unsupported(&io_error) => {
+                for key in &keys {
+                    self.remove(key)?;
+                }
+                Ok(())
+            }
+            Err((code, io_error)) => Err(MapError::SyscallError {
+                call: "bpf_map_delete_batch".to_owned(),
+                code,
+                io_error,
+            }),
+        }
+    }
+}
+
+/// An iterator over the key-value pairs of a [`HashMap`], backed by the kernel's
+/// `BPF_MAP_LOOKUP_BATCH` command. Returned by [`HashMap::iter_batch`].
+pub struct MapBatchIter<'coll, T: Deref<Target = Map>, K, V> {
+    map: &'coll HashMap<T, K, V>,
+    batch_size: usize,
+    in_batch: Option<Vec<u8>>,
+    pending: VecDeque<(K, V)>,
+    done: bool,
+    fallback: Option<MapIter<'coll, K, V>>,
+}
+
+impl<'coll, T: Deref<Target = Map>, K: Pod, V: Pod> MapBatchIter<'coll, T, K, V> {
+    fn new(map: &'coll HashMap<T, K, V>, batch_size: usize) -> MapBatchIter<'coll, T, K, V> {
+        MapBatchIter {
+            map,
+            // A batch size of 0 would make no progress per syscall, spinning forever.
+            batch_size: batch_size.max(1),
+            in_batch: None,
+            pending: VecDeque::new(),
+            done: false,
+            fallback: None,
+        }
+    }
+
+    fn fetch_next_batch(&mut self) -> Result<(), MapError> {
+        let fd = self.map.inner.deref().fd_or_err()?;
+        match bpf_map_lookup_batch::<K, V>(fd, self.in_batch.take(), self.batch_size) {
+            Ok((items, out_batch, done)) => {
+                self.in_batch = Some(out_batch);
+                self.pending.extend(items);
+                self.done = done;
+                Ok(())
+            }
+            Err((_, io_error)) if is_batch_unsupported(&io_error) => {
+                // The kernel doesn't support batch lookups on this map type; fall back to the
+                // one-by-one path for the rest of the iteration.
+                self.fallback = Some(MapIter::new(self.map));
+                self.done = true;
+                Ok(())
+            }
+            Err((code, io_error)) => Err(MapError::SyscallError {
+                call: "bpf_map_lookup_batch".to_owned(),
+                code,
+                io_error,
+            }),
+        }
+    }
+}
+
+impl<'coll, T: Deref<Target = Map>, K: Pod, V: Pod> Iterator for MapBatchIter<'coll, T, K, V> {
+    type Item = Result<(K, V), MapError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(fallback) = &mut self.fallback {
+            return fallback.next();
+        }
+        if let Some(item) = self.pending.pop_front() {
+            return Some(Ok(item));
+        }
+        if self.done {
+            return None;
+        }
+        if let Err(e) = self.fetch_next_batch() {
+            self.done = true;
+            return Some(Err(e));
+        }
+        if let Some(fallback) = &mut self.fallback {
+            return fallback.next();
+        }
+        self.pending.pop_front().map(Ok)
+    }
 }
 
 impl<T: Deref<Target = Map>, K: Pod, V: Pod> IterableMap<K, V> for HashMap<T, K, V> {
@@ -163,20 +375,185 @@ impl<'a, K: Pod, V: Pod> TryFrom<&'a mut Map> for HashMap<&'a mut Map, K, V> {
     }
 }
 
+/// A hash map stored inside the kernel, with one independent value slot per possible CPU.
+/// Wraps `BPF_MAP_TYPE_PERCPU_HASH` and, like [`HashMap`], its LRU-evicting counterpart
+/// `BPF_MAP_TYPE_LRU_PERCPU_HASH`.
+///
+/// eBPF programs running on different CPUs see and update their own slot, so reads and writes
+/// never need to synchronize with other CPUs. This is commonly used for counters and other
+/// statistics that are cheap to combine in user space.
+///
+/// The types of the keys and values must be plain old data (POD), meaning that they
+/// must be safely convertible to and from byte slices.
+///
+/// # Example
+///
+/// ```no_run
+/// # let bpf = aya::Bpf::load(&[], None)?;
+/// use aya::maps::{PerCpuHashMap, PerCpuValues};
+/// use std::convert::TryFrom;
+///
+/// let mut hm = PerCpuHashMap::<_, u32, u64>::try_from(bpf.map_mut("PACKET_COUNTS")?)?;
+/// if let Some(counts) = unsafe { hm.get(&1u32, 0) }? {
+///     for (cpu, count) in counts.iter().enumerate() {
+///         println!("cpu {}: {}", cpu, count);
+///     }
+/// }
+/// # Ok::<(), aya::BpfError>(())
+/// ```
+pub struct PerCpuHashMap<T: Deref<Target = Map>, K, V> {
+    inner: T,
+    _k: PhantomData<K>,
+    _v: PhantomData<V>,
+}
+
+impl<T: Deref<Target = Map>, K: Pod, V: Pod> PerCpuHashMap<T, K, V> {
+    pub(crate) fn new(map: T) -> Result<PerCpuHashMap<T, K, V>, MapError> {
+        let map_type = map.obj.def.map_type;
+
+        // validate the map definition
+        check_hash_map_type(
+            map_type,
+            &[
+                BPF_MAP_TYPE_PERCPU_HASH as u32,
+                BPF_MAP_TYPE_LRU_PERCPU_HASH as u32,
+            ],
+        )?;
+        let size = mem::size_of::<K>();
+        let expected = map.obj.def.key_size as usize;
+        if size != expected {
+            return Err(MapError::InvalidKeySize { size, expected });
+        }
+
+        let size = mem::size_of::<V>();
+        let expected = map.obj.def.value_size as usize;
+        if size != expected {
+            return Err(MapError::InvalidValueSize { size, expected });
+        }
+
+        // make sure the map has been created
+        let _fd = map.fd_or_err()?;
+
+        Ok(PerCpuHashMap {
+            inner: map,
+            _k: PhantomData,
+            _v: PhantomData,
+        })
+    }
+
+    /// Returns a slice of values - one for each CPU - associated with the key.
+    pub unsafe fn get(&self, key: &K, flags: u64) -> Result<Option<PerCpuValues<V>>, MapError> {
+        let fd = self.inner.deref().fd_or_err()?;
+        bpf_map_lookup_elem_per_cpu(fd, key, flags).map_err(|(code, io_error)| {
+            MapError::SyscallError {
+                call: "bpf_map_lookup_elem".to_owned(),
+                code,
+                io_error,
+            }
+        })
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order. The iterator item type is
+    /// `Result<(K, PerCpuValues<V>), MapError>`.
+    pub unsafe fn iter<'coll>(&'coll self) -> MapIter<'coll, K, PerCpuValues<V>> {
+        MapIter::new(self)
+    }
+
+    /// An iterator visiting all keys in arbitrary order. The iterator element type is
+    /// `Result<K, MapError>`.
+    pub unsafe fn keys<'coll>(&'coll self) -> MapKeys<'coll, K, PerCpuValues<V>> {
+        MapKeys::new(self)
+    }
+}
+
+impl<T: DerefMut<Target = Map>, K: Pod, V: Pod> PerCpuHashMap<T, K, V> {
+    /// Inserts a slice of values - one for each CPU - for the given key.
+    pub fn insert(&mut self, key: K, values: PerCpuValues<V>, flags: u64) -> Result<(), MapError> {
+        let fd = self.inner.deref_mut().fd_or_err()?;
+        bpf_map_update_elem_per_cpu(fd, &key, &values, flags).map_err(|(code, io_error)| {
+            MapError::SyscallError {
+                call: "bpf_map_update_elem".to_owned(),
+                code,
+                io_error,
+            }
+        })?;
+        Ok(())
+    }
+
+    /// Removes a key from the map.
+    pub fn remove(&mut self, key: &K) -> Result<(), MapError> {
+        let fd = self.inner.deref_mut().fd_or_err()?;
+        bpf_map_delete_elem(fd, key)
+            .map(|_| ())
+            .map_err(|(code, io_error)| MapError::SyscallError {
+                call: "bpf_map_delete_elem".to_owned(),
+                code,
+                io_error,
+            })
+    }
+}
+
+impl<T: Deref<Target = Map>, K: Pod, V: Pod> IterableMap<K, PerCpuValues<V>>
+    for PerCpuHashMap<T, K, V>
+{
+    fn fd(&self) -> Result<RawFd, MapError> {
+        self.inner.deref().fd_or_err()
+    }
+
+    unsafe fn get(&self, key: &K) -> Result<Option<PerCpuValues<V>>, MapError> {
+        PerCpuHashMap::get(self, key, 0)
+    }
+}
+
+impl<K: Pod, V: Pod> TryFrom<MapRef> for PerCpuHashMap<MapRef, K, V> {
+    type Error = MapError;
+
+    fn try_from(a: MapRef) -> Result<PerCpuHashMap<MapRef, K, V>, MapError> {
+        PerCpuHashMap::new(a)
+    }
+}
+
+impl<K: Pod, V: Pod> TryFrom<MapRefMut> for PerCpuHashMap<MapRefMut, K, V> {
+    type Error = MapError;
+
+    fn try_from(a: MapRefMut) -> Result<PerCpuHashMap<MapRefMut, K, V>, MapError> {
+        PerCpuHashMap::new(a)
+    }
+}
+
+impl<'a, K: Pod, V: Pod> TryFrom<&'a Map> for PerCpuHashMap<&'a Map, K, V> {
+    type Error = MapError;
+
+    fn try_from(a: &'a Map) -> Result<PerCpuHashMap<&'a Map, K, V>, MapError> {
+        PerCpuHashMap::new(a)
+    }
+}
+
+impl<'a, K: Pod, V: Pod> TryFrom<&'a mut Map> for PerCpuHashMap<&'a mut Map, K, V> {
+    type Error = MapError;
+
+    fn try_from(a: &'a mut Map) -> Result<PerCpuHashMap<&'a mut Map, K, V>, MapError> {
+        PerCpuHashMap::new(a)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io;
+    use std::{cell::Cell, io, ptr};
 
-    use libc::{EFAULT, ENOENT};
+    use libc::{EAGAIN, EFAULT, EINVAL, ENOENT};
 
     use crate::{
         bpf_map_def,
         generated::{
             bpf_attr, bpf_cmd,
-            bpf_map_type::{BPF_MAP_TYPE_HASH, BPF_MAP_TYPE_PERF_EVENT_ARRAY},
+            bpf_map_type::{
+                BPF_MAP_TYPE_HASH, BPF_MAP_TYPE_LRU_HASH, BPF_MAP_TYPE_LRU_PERCPU_HASH,
+                BPF_MAP_TYPE_PERCPU_HASH, BPF_MAP_TYPE_PERF_EVENT_ARRAY,
+            },
         },
         obj,
-        sys::{override_syscall, SysResult, Syscall},
+        sys::{override_nr_cpus as set_test_nr_cpus, override_syscall, SysResult, Syscall},
     };
 
     use super::*;
@@ -196,6 +573,16 @@ mod tests {
         }
     }
 
+    fn new_percpu_obj_map(name: &str) -> obj::Map {
+        obj::Map {
+            def: bpf_map_def {
+                map_type: BPF_MAP_TYPE_PERCPU_HASH as u32,
+                ..new_obj_map(name).def
+            },
+            ..new_obj_map(name)
+        }
+    }
+
     fn sys_error(value: i32) -> SysResult {
         Err((-1, io::Error::from_raw_os_error(value)))
     }
@@ -406,6 +793,32 @@ mod tests {
         unsafe { *value = ret };
     }
 
+    // Per-CPU value slots are laid out back to back in the buffer pointed to by `attr.value`,
+    // each one rounded up to a multiple of 8 bytes - mirroring `sys::bpf_map_*_elem_per_cpu`.
+    fn percpu_value_ptr<T>(attr: &bpf_attr) -> *mut u8 {
+        unsafe { attr.__bindgen_anon_2.__bindgen_anon_1.value as *mut u8 }
+    }
+
+    fn percpu_slot_size<T>() -> usize {
+        (mem::size_of::<T>() + 7) & !7
+    }
+
+    fn set_percpu_values<T: Copy>(attr: &bpf_attr, values: &[T]) {
+        let ptr = percpu_value_ptr::<T>(attr);
+        let slot_size = percpu_slot_size::<T>();
+        for (cpu, value) in values.iter().enumerate() {
+            unsafe { ptr::write_unaligned(ptr.add(cpu * slot_size) as *mut T, *value) };
+        }
+    }
+
+    fn get_percpu_values<T: Copy>(attr: &bpf_attr, num_cpus: usize) -> Vec<T> {
+        let ptr = percpu_value_ptr::<T>(attr);
+        let slot_size = percpu_slot_size::<T>();
+        (0..num_cpus)
+            .map(|cpu| unsafe { ptr::read_unaligned(ptr.add(cpu * slot_size) as *const T) })
+            .collect()
+    }
+
     #[test]
     fn test_keys_empty() {
         override_syscall(|call| match call {
@@ -633,4 +1046,472 @@ mod tests {
         assert!(matches!(iter.next(), Some(Ok((30, 300)))));
         assert!(matches!(iter.next(), None));
     }
+
+    #[test]
+    fn test_percpu_values_wrong_len() {
+        set_test_nr_cpus(4);
+        assert!(matches!(
+            PerCpuValues::<u32>::try_from(vec![1, 2, 3]),
+            Err(MapError::InvalidValueSize {
+                size: 3,
+                expected: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn test_percpu_values_ok() {
+        set_test_nr_cpus(2);
+        assert!(PerCpuValues::<u32>::try_from(vec![1, 2]).is_ok());
+    }
+
+    #[test]
+    fn test_percpu_wrong_key_size() {
+        let map = Map {
+            obj: new_percpu_obj_map("TEST"),
+            fd: None,
+        };
+        assert!(matches!(
+            PerCpuHashMap::<_, u8, u32>::new(&map),
+            Err(MapError::InvalidKeySize {
+                size: 1,
+                expected: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn test_percpu_wrong_value_size() {
+        let map = Map {
+            obj: new_percpu_obj_map("TEST"),
+            fd: None,
+        };
+        assert!(matches!(
+            PerCpuHashMap::<_, u32, u16>::new(&map),
+            Err(MapError::InvalidValueSize {
+                size: 2,
+                expected: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn test_percpu_try_from_wrong_map() {
+        let map = Map {
+            obj: new_obj_map("TEST"),
+            fd: None,
+        };
+
+        assert!(matches!(
+            PerCpuHashMap::<_, u32, u32>::try_from(&map),
+            Err(MapError::InvalidMapType { .. })
+        ));
+    }
+
+    #[test]
+    fn test_percpu_new_ok() {
+        let mut map = Map {
+            obj: new_percpu_obj_map("TEST"),
+            fd: Some(42),
+        };
+
+        assert!(PerCpuHashMap::<_, u32, u32>::new(&mut map).is_ok());
+    }
+
+    #[test]
+    fn test_percpu_remove_ok() {
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_DELETE_ELEM,
+                ..
+            } => Ok(1),
+            _ => sys_error(EFAULT),
+        });
+
+        let mut map = Map {
+            obj: new_percpu_obj_map("TEST"),
+            fd: Some(42),
+        };
+        let mut hm = PerCpuHashMap::<_, u32, u32>::new(&mut map).unwrap();
+
+        assert!(hm.remove(&1).is_ok());
+    }
+
+    #[test]
+    fn test_percpu_get_ok() {
+        set_test_nr_cpus(3);
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_LOOKUP_ELEM,
+                attr,
+            } => {
+                set_percpu_values(&attr, &[100u32, 200, 300]);
+                Ok(1)
+            }
+            _ => sys_error(EFAULT),
+        });
+
+        let map = Map {
+            obj: new_percpu_obj_map("TEST"),
+            fd: Some(42),
+        };
+        let hm = PerCpuHashMap::<_, u32, u32>::new(&map).unwrap();
+
+        let values = unsafe { hm.get(&1, 0) }.unwrap().unwrap();
+        assert_eq!(values.to_vec(), vec![100, 200, 300]);
+    }
+
+    #[test]
+    fn test_percpu_get_not_found() {
+        set_test_nr_cpus(2);
+        override_syscall(|_| sys_error(ENOENT));
+
+        let map = Map {
+            obj: new_percpu_obj_map("TEST"),
+            fd: Some(42),
+        };
+        let hm = PerCpuHashMap::<_, u32, u32>::new(&map).unwrap();
+
+        assert!(matches!(unsafe { hm.get(&1, 0) }, Ok(None)));
+    }
+
+    #[test]
+    fn test_percpu_insert_ok() {
+        set_test_nr_cpus(3);
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_UPDATE_ELEM,
+                attr,
+            } => {
+                assert_eq!(get_percpu_values::<u32>(&attr, 3), vec![10, 20, 30]);
+                Ok(1)
+            }
+            _ => sys_error(EFAULT),
+        });
+
+        let mut map = Map {
+            obj: new_percpu_obj_map("TEST"),
+            fd: Some(42),
+        };
+        let mut hm = PerCpuHashMap::<_, u32, u32>::new(&mut map).unwrap();
+        let values = PerCpuValues::try_from(vec![10u32, 20, 30]).unwrap();
+
+        assert!(hm.insert(1, values, 0).is_ok());
+    }
+
+    #[test]
+    fn test_new_ok_lru() {
+        let mut map = Map {
+            obj: obj::Map {
+                def: bpf_map_def {
+                    map_type: BPF_MAP_TYPE_LRU_HASH as u32,
+                    ..new_obj_map("TEST").def
+                },
+                ..new_obj_map("TEST")
+            },
+            fd: Some(42),
+        };
+
+        assert!(HashMap::<_, u32, u32>::new(&mut map).is_ok());
+    }
+
+    #[test]
+    fn test_try_from_wrong_map_percpu() {
+        let map = Map {
+            obj: new_percpu_obj_map("TEST"),
+            fd: None,
+        };
+
+        assert!(matches!(
+            HashMap::<_, u32, u32>::try_from(&map),
+            Err(MapError::InvalidMapType { .. })
+        ));
+    }
+
+    #[test]
+    fn test_percpu_new_ok_lru() {
+        let mut map = Map {
+            obj: obj::Map {
+                def: bpf_map_def {
+                    map_type: BPF_MAP_TYPE_LRU_PERCPU_HASH as u32,
+                    ..new_percpu_obj_map("TEST").def
+                },
+                ..new_percpu_obj_map("TEST")
+            },
+            fd: Some(42),
+        };
+
+        assert!(PerCpuHashMap::<_, u32, u32>::new(&mut map).is_ok());
+    }
+
+    #[test]
+    fn test_insert_batch_falls_back_on_einval() {
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_UPDATE_BATCH,
+                ..
+            } => sys_error(EINVAL),
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_UPDATE_ELEM,
+                ..
+            } => Ok(1),
+            _ => sys_error(EFAULT),
+        });
+
+        let mut map = Map {
+            obj: new_obj_map("TEST"),
+            fd: Some(42),
+        };
+        let mut hm = HashMap::<_, u32, u32>::new(&mut map).unwrap();
+
+        assert!(hm.insert_batch(vec![(1, 10), (2, 20)], 0).is_ok());
+    }
+
+    #[test]
+    fn test_remove_batch_falls_back_on_einval() {
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_DELETE_BATCH,
+                ..
+            } => sys_error(EINVAL),
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_DELETE_ELEM,
+                ..
+            } => Ok(1),
+            _ => sys_error(EFAULT),
+        });
+
+        let mut map = Map {
+            obj: new_obj_map("TEST"),
+            fd: Some(42),
+        };
+        let mut hm = HashMap::<_, u32, u32>::new(&mut map).unwrap();
+
+        assert!(hm.remove_batch(vec![1, 2]).is_ok());
+    }
+
+    #[test]
+    fn test_iter_batch_falls_back_on_einval() {
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_LOOKUP_BATCH,
+                ..
+            } => sys_error(EINVAL),
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_GET_NEXT_KEY,
+                attr,
+            } => get_next_key(&attr),
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_LOOKUP_ELEM,
+                attr,
+            } => lookup_elem(&attr),
+            _ => sys_error(EFAULT),
+        });
+
+        let map = Map {
+            obj: new_obj_map("TEST"),
+            fd: Some(42),
+        };
+        let hm = HashMap::<_, u32, u32>::new(&map).unwrap();
+
+        let items = unsafe { hm.iter_batch(10) }
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(&items, &[(10, 100), (20, 200), (30, 300)])
+    }
+
+    fn set_batch_kvs<K: Copy, V: Copy>(attr: &bpf_attr, kvs: &[(K, V)]) {
+        let keys = unsafe { attr.batch.keys } as *mut K;
+        let values = unsafe { attr.batch.values } as *mut V;
+        for (i, (key, value)) in kvs.iter().enumerate() {
+            unsafe {
+                ptr::write_unaligned(keys.add(i), *key);
+                ptr::write_unaligned(values.add(i), *value);
+            }
+        }
+    }
+
+    fn batch_keys<K: Copy>(attr: &bpf_attr, count: usize) -> Vec<K> {
+        let keys = unsafe { attr.batch.keys } as *const K;
+        (0..count)
+            .map(|i| unsafe { ptr::read_unaligned(keys.add(i)) })
+            .collect()
+    }
+
+    fn batch_values<V: Copy>(attr: &bpf_attr, count: usize) -> Vec<V> {
+        let values = unsafe { attr.batch.values } as *const V;
+        (0..count)
+            .map(|i| unsafe { ptr::read_unaligned(values.add(i)) })
+            .collect()
+    }
+
+    fn set_out_batch<T: Copy>(attr: &bpf_attr, value: T) {
+        let out_batch = unsafe { attr.batch.out_batch } as *mut T;
+        unsafe { ptr::write_unaligned(out_batch, value) };
+    }
+
+    fn in_batch<T: Copy>(attr: &bpf_attr) -> T {
+        let in_batch = unsafe { attr.batch.in_batch } as *const T;
+        unsafe { ptr::read_unaligned(in_batch) }
+    }
+
+    #[test]
+    fn test_insert_batch_ok() {
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_UPDATE_BATCH,
+                attr,
+            } => {
+                assert_eq!(batch_keys::<u32>(&attr, 2), vec![1, 2]);
+                assert_eq!(batch_values::<u32>(&attr, 2), vec![10, 20]);
+                Ok(0)
+            }
+            _ => sys_error(EFAULT),
+        });
+
+        let mut map = Map {
+            obj: new_obj_map("TEST"),
+            fd: Some(42),
+        };
+        let mut hm = HashMap::<_, u32, u32>::new(&mut map).unwrap();
+
+        assert!(hm.insert_batch(vec![(1, 10), (2, 20)], 0).is_ok());
+    }
+
+    #[test]
+    fn test_remove_batch_ok() {
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_DELETE_BATCH,
+                attr,
+            } => {
+                assert_eq!(batch_keys::<u32>(&attr, 2), vec![1, 2]);
+                Ok(0)
+            }
+            _ => sys_error(EFAULT),
+        });
+
+        let mut map = Map {
+            obj: new_obj_map("TEST"),
+            fd: Some(42),
+        };
+        let mut hm = HashMap::<_, u32, u32>::new(&mut map).unwrap();
+
+        assert!(hm.remove_batch(vec![1, 2]).is_ok());
+    }
+
+    #[test]
+    fn test_iter_batch_ok() {
+        // Simulates a map with 3 entries returned over two pages of at most 2 entries each: the
+        // first page is full (more to come, signalled by a plain `Ok` return), the second is a
+        // partial page (end of the map, signalled by `-ENOENT`). Both pages report how many
+        // pairs they actually filled in via the in/out `attr.batch.count` field, the way the
+        // real kernel does, rather than via the syscall's return value.
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_LOOKUP_BATCH,
+                attr,
+            } => match in_batch::<u32>(&attr) {
+                0 => {
+                    set_batch_kvs(&attr, &[(10u32, 100u32), (20, 200)]);
+                    set_out_batch(&attr, 20u32);
+                    attr.batch.count = 2;
+                    Ok(0)
+                }
+                20 => {
+                    set_batch_kvs(&attr, &[(30u32, 300u32)]);
+                    set_out_batch(&attr, 30u32);
+                    attr.batch.count = 1;
+                    sys_error(ENOENT)
+                }
+                _ => sys_error(EFAULT),
+            },
+            _ => sys_error(EFAULT),
+        });
+
+        let map = Map {
+            obj: new_obj_map("TEST"),
+            fd: Some(42),
+        };
+        let hm = HashMap::<_, u32, u32>::new(&map).unwrap();
+
+        let items = unsafe { hm.iter_batch(2) }
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(&items, &[(10, 100), (20, 200), (30, 300)])
+    }
+
+    #[test]
+    fn test_iter_batch_retries_on_eagain() {
+        // A bucket being concurrently modified makes the kernel return `-EAGAIN` for the same
+        // cursor; the caller is expected to retry rather than treat it as a fatal error.
+        let retried = Cell::new(false);
+        override_syscall(move |call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_LOOKUP_BATCH,
+                attr,
+            } => {
+                if !retried.get() {
+                    retried.set(true);
+                    return sys_error(EAGAIN);
+                }
+                set_batch_kvs(&attr, &[(10u32, 100u32)]);
+                set_out_batch(&attr, 10u32);
+                attr.batch.count = 1;
+                sys_error(ENOENT)
+            }
+            _ => sys_error(EFAULT),
+        });
+
+        let map = Map {
+            obj: new_obj_map("TEST"),
+            fd: Some(42),
+        };
+        let hm = HashMap::<_, u32, u32>::new(&map).unwrap();
+
+        let items = unsafe { hm.iter_batch(2) }
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(&items, &[(10, 100)])
+    }
+
+    #[test]
+    fn test_iter_batch_cursor_wider_than_key() {
+        // The kernel's hash-table batch cursor is its own internal bucket index, not a copy of
+        // the key - it doesn't fit in a 1-byte `u8` key's `in_batch`/`out_batch` buffer, so the
+        // mock here writes a `u64` cursor the way a real kernel would, to make sure the buffers
+        // backing those pointers are sized for the cursor rather than for `K`.
+        override_syscall(|call| match call {
+            Syscall::Bpf {
+                cmd: bpf_cmd::BPF_MAP_LOOKUP_BATCH,
+                attr,
+            } => match in_batch::<u64>(&attr) {
+                0 => {
+                    set_batch_kvs(&attr, &[(1u8, 100u32)]);
+                    set_out_batch(&attr, u64::MAX);
+                    attr.batch.count = 1;
+                    sys_error(ENOENT)
+                }
+                _ => sys_error(EFAULT),
+            },
+            _ => sys_error(EFAULT),
+        });
+
+        let map = Map {
+            obj: obj::Map {
+                def: bpf_map_def {
+                    key_size: 1,
+                    ..new_obj_map("TEST").def
+                },
+                ..new_obj_map("TEST")
+            },
+            fd: Some(42),
+        };
+        let hm = HashMap::<_, u8, u32>::new(&map).unwrap();
+
+        let items = unsafe { hm.iter_batch(2) }
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(&items, &[(1, 100)])
+    }
 }
@@ -0,0 +1,339 @@
+//! Safe wrappers around the `bpf(2)` syscall commands used by the `maps` module.
+use std::{io, mem, os::unix::io::RawFd, ptr};
+
+use crate::{
+    generated::{bpf_attr, bpf_cmd},
+    maps::hash_map::PerCpuValues,
+    Pod,
+};
+
+/// The result of a single `bpf(2)` call: the raw return value on success, or the `errno` and
+/// the corresponding [`io::Error`] on failure.
+pub(crate) type SysResult = Result<i64, (i32, io::Error)>;
+
+/// A `bpf(2)` call, as seen by [`override_syscall`] in tests. `attr` is borrowed mutably so that
+/// overrides can write kernel-populated out-parameters (e.g. `attr.batch.count`) back into the
+/// caller's `bpf_attr`, the same way the real syscall does.
+#[derive(Debug)]
+pub(crate) enum Syscall<'a> {
+    Bpf { cmd: bpf_cmd, attr: &'a mut bpf_attr },
+}
+
+#[cfg(not(test))]
+fn sys_bpf(cmd: bpf_cmd, attr: &mut bpf_attr) -> SysResult {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut bpf_attr,
+            mem::size_of::<bpf_attr>(),
+        )
+    };
+    if ret < 0 {
+        Err((-1, io::Error::last_os_error()))
+    } else {
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+fn sys_bpf(cmd: bpf_cmd, attr: &mut bpf_attr) -> SysResult {
+    TEST_SYSCALL_OVERRIDE.with(|c| (*c.borrow())(Syscall::Bpf { cmd, attr }))
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_SYSCALL_OVERRIDE: std::cell::RefCell<Box<dyn for<'a> Fn(Syscall<'a>) -> SysResult>> =
+        std::cell::RefCell::new(Box::new(|_| Ok(1)));
+}
+
+/// Overrides the `bpf(2)` syscall with `f` for the remainder of the test. Used by the `maps`
+/// module's unit tests to simulate kernel responses without actually talking to the kernel.
+#[cfg(test)]
+pub(crate) fn override_syscall<F: for<'a> Fn(Syscall<'a>) -> SysResult + 'static>(f: F) {
+    TEST_SYSCALL_OVERRIDE.with(|c| *c.borrow_mut() = Box::new(f));
+}
+
+/// Retries `sys_bpf` on `EAGAIN`, up to `MAX_EAGAIN_RETRIES` times. The batch commands
+/// (`BPF_MAP_{LOOKUP,UPDATE,DELETE}_BATCH`) use `-EAGAIN` to mean "a bucket was modified
+/// concurrently, retry this same cursor", not a fatal error, so it shouldn't be surfaced to
+/// callers the way other syscall failures are.
+const MAX_EAGAIN_RETRIES: u32 = 10;
+
+fn sys_bpf_batch(cmd: bpf_cmd, attr: &mut bpf_attr) -> SysResult {
+    for _ in 0..MAX_EAGAIN_RETRIES {
+        match sys_bpf(cmd, attr) {
+            Err((_, io_error)) if io_error.raw_os_error() == Some(libc::EAGAIN) => continue,
+            result => return result,
+        }
+    }
+    sys_bpf(cmd, attr)
+}
+
+fn new_attr() -> bpf_attr {
+    unsafe { mem::zeroed() }
+}
+
+/// Returns the value associated with `key`, or `None` if the key doesn't exist.
+pub(crate) fn bpf_map_lookup_elem<K: Pod, V: Pod>(
+    fd: RawFd,
+    key: &K,
+    flags: u64,
+) -> Result<Option<V>, (i32, io::Error)> {
+    let mut value = mem::MaybeUninit::<V>::zeroed();
+    let mut attr = new_attr();
+    attr.__bindgen_anon_2.map_fd = fd as u32;
+    attr.__bindgen_anon_2.key = key as *const K as u64;
+    attr.__bindgen_anon_2.__bindgen_anon_1.value = value.as_mut_ptr() as u64;
+    attr.__bindgen_anon_2.flags = flags;
+
+    match sys_bpf(bpf_cmd::BPF_MAP_LOOKUP_ELEM, &mut attr) {
+        Ok(_) => Ok(Some(unsafe { value.assume_init() })),
+        Err((_, io_error)) if io_error.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Inserts or updates the value associated with `key`.
+pub(crate) fn bpf_map_update_elem<K: Pod, V: Pod>(
+    fd: RawFd,
+    key: &K,
+    value: &V,
+    flags: u64,
+) -> Result<i64, (i32, io::Error)> {
+    let mut attr = new_attr();
+    attr.__bindgen_anon_2.map_fd = fd as u32;
+    attr.__bindgen_anon_2.key = key as *const K as u64;
+    attr.__bindgen_anon_2.__bindgen_anon_1.value = value as *const V as u64;
+    attr.__bindgen_anon_2.flags = flags;
+
+    sys_bpf(bpf_cmd::BPF_MAP_UPDATE_ELEM, &mut attr)
+}
+
+/// The size, in bytes, of the `in_batch`/`out_batch` cursor buffers passed to the
+/// `BPF_MAP_*_BATCH` commands.
+///
+/// The uapi only documents these as "two opaque values used to indicate from/to which bucket to
+/// resume the batch" - for the hash-table map family this crate's `HashMap` wraps, the kernel's
+/// `htab_map_lookup_batch` fills them in with its own internal bucket-index cursor, which is
+/// unrelated to the map's key layout (unlike the generic array-map batch path, which does copy
+/// the key). Sizing the buffer to `mem::size_of::<K>()` is therefore wrong and unsound for any
+/// `K` smaller than that cursor: the kernel writes `sizeof(cursor)` bytes into `out_batch`
+/// regardless, so a too-small allocation gets written past its end.
+const BATCH_CURSOR_SIZE: usize = mem::size_of::<u64>();
+
+/// Looks up at most `count` key-value pairs starting from `in_batch` (or the beginning of the
+/// map if `in_batch` is `None`) via the kernel's `BPF_MAP_LOOKUP_BATCH` command.
+///
+/// Returns the pairs found, the opaque cursor to resume from on the next call, and whether the
+/// end of the map has been reached. The syscall's return value itself carries no count - the
+/// kernel reports how many pairs it actually filled in via the in/out `attr.batch.count` field,
+/// including on the final, possibly partial, page that it signals by returning `-ENOENT`.
+pub(crate) fn bpf_map_lookup_batch<K: Pod, V: Pod>(
+    fd: RawFd,
+    in_batch: Option<Vec<u8>>,
+    count: usize,
+) -> Result<(Vec<(K, V)>, Vec<u8>, bool), (i32, io::Error)> {
+    let mut out_batch = vec![0u8; BATCH_CURSOR_SIZE];
+    let in_batch = in_batch.unwrap_or_else(|| vec![0u8; BATCH_CURSOR_SIZE]);
+    let mut keys = vec![0u8; mem::size_of::<K>() * count];
+    let mut values = vec![0u8; mem::size_of::<V>() * count];
+
+    let mut attr = new_attr();
+    attr.batch.in_batch = in_batch.as_ptr() as u64;
+    attr.batch.out_batch = out_batch.as_mut_ptr() as u64;
+    attr.batch.keys = keys.as_mut_ptr() as u64;
+    attr.batch.values = values.as_mut_ptr() as u64;
+    attr.batch.count = count as u32;
+    attr.batch.map_fd = fd as u32;
+
+    let done = match sys_bpf_batch(bpf_cmd::BPF_MAP_LOOKUP_BATCH, &mut attr) {
+        Ok(_) => false,
+        Err((_, io_error)) if io_error.raw_os_error() == Some(libc::ENOENT) => true,
+        Err(e) => return Err(e),
+    };
+    let found = attr.batch.count as usize;
+
+    let pairs = (0..found)
+        .map(|i| unsafe {
+            let key = ptr::read_unaligned(keys.as_ptr().add(i * mem::size_of::<K>()) as *const K);
+            let value =
+                ptr::read_unaligned(values.as_ptr().add(i * mem::size_of::<V>()) as *const V);
+            (key, value)
+        })
+        .collect();
+
+    Ok((pairs, out_batch, done))
+}
+
+/// Inserts or updates `entries` at once via the kernel's `BPF_MAP_UPDATE_BATCH` command.
+pub(crate) fn bpf_map_update_batch<K: Pod, V: Pod>(
+    fd: RawFd,
+    entries: &[(K, V)],
+    flags: u64,
+) -> Result<(), (i32, io::Error)> {
+    let count = entries.len();
+    let mut keys = vec![0u8; mem::size_of::<K>() * count];
+    let mut values = vec![0u8; mem::size_of::<V>() * count];
+    for (i, (key, value)) in entries.iter().enumerate() {
+        unsafe {
+            ptr::write_unaligned(
+                keys.as_mut_ptr().add(i * mem::size_of::<K>()) as *mut K,
+                *key,
+            );
+            ptr::write_unaligned(
+                values.as_mut_ptr().add(i * mem::size_of::<V>()) as *mut V,
+                *value,
+            );
+        }
+    }
+
+    let mut attr = new_attr();
+    attr.batch.keys = keys.as_mut_ptr() as u64;
+    attr.batch.values = values.as_mut_ptr() as u64;
+    attr.batch.count = count as u32;
+    attr.batch.map_fd = fd as u32;
+    attr.batch.elem_flags = flags;
+
+    sys_bpf_batch(bpf_cmd::BPF_MAP_UPDATE_BATCH, &mut attr).map(|_| ())
+}
+
+/// Deletes `keys` at once via the kernel's `BPF_MAP_DELETE_BATCH` command.
+pub(crate) fn bpf_map_delete_batch<K: Pod>(fd: RawFd, keys: &[K]) -> Result<(), (i32, io::Error)> {
+    let count = keys.len();
+    let mut keys_buf = vec![0u8; mem::size_of::<K>() * count];
+    for (i, key) in keys.iter().enumerate() {
+        unsafe {
+            ptr::write_unaligned(
+                keys_buf.as_mut_ptr().add(i * mem::size_of::<K>()) as *mut K,
+                *key,
+            );
+        }
+    }
+
+    let mut attr = new_attr();
+    attr.batch.keys = keys_buf.as_mut_ptr() as u64;
+    attr.batch.count = count as u32;
+    attr.batch.map_fd = fd as u32;
+
+    sys_bpf_batch(bpf_cmd::BPF_MAP_DELETE_BATCH, &mut attr).map(|_| ())
+}
+
+/// Deletes the entry associated with `key`.
+pub(crate) fn bpf_map_delete_elem<K: Pod>(fd: RawFd, key: &K) -> Result<i64, (i32, io::Error)> {
+    let mut attr = new_attr();
+    attr.__bindgen_anon_2.map_fd = fd as u32;
+    attr.__bindgen_anon_2.key = key as *const K as u64;
+
+    sys_bpf(bpf_cmd::BPF_MAP_DELETE_ELEM, &mut attr)
+}
+
+/// Returns the key that follows `key` in iteration order, or the first key if `key` is `None`.
+/// Returns `None` once there are no more keys.
+pub(crate) fn bpf_map_get_next_key<K: Pod>(
+    fd: RawFd,
+    key: Option<&K>,
+) -> Result<Option<K>, (i32, io::Error)> {
+    let mut next_key = mem::MaybeUninit::<K>::zeroed();
+    let mut attr = new_attr();
+    attr.__bindgen_anon_2.map_fd = fd as u32;
+    if let Some(key) = key {
+        attr.__bindgen_anon_2.key = key as *const K as u64;
+    }
+    attr.__bindgen_anon_2.__bindgen_anon_1.next_key = next_key.as_mut_ptr() as u64;
+
+    match sys_bpf(bpf_cmd::BPF_MAP_GET_NEXT_KEY, &mut attr) {
+        Ok(_) => Ok(Some(unsafe { next_key.assume_init() })),
+        Err((_, io_error)) if io_error.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_NR_CPUS: std::cell::Cell<Option<usize>> = std::cell::Cell::new(None);
+}
+
+/// Pins the value returned by [`nr_cpus`] for the remainder of the test, instead of depending on
+/// the number of CPUs available on the machine running the test suite.
+#[cfg(test)]
+pub(crate) fn override_nr_cpus(nr_cpus: usize) {
+    TEST_NR_CPUS.with(|c| c.set(Some(nr_cpus)));
+}
+
+/// Returns the number of possible CPUs, as reported by the kernel. This is the number of value
+/// slots the kernel allocates for each key in a per-CPU map, regardless of how many CPUs are
+/// actually online.
+pub(crate) fn nr_cpus() -> Result<usize, (i32, io::Error)> {
+    #[cfg(test)]
+    if let Some(nr_cpus) = TEST_NR_CPUS.with(|c| c.get()) {
+        return Ok(nr_cpus);
+    }
+    let nr_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+    if nr_cpus < 0 {
+        return Err((-1, io::Error::last_os_error()));
+    }
+    Ok(nr_cpus as usize)
+}
+
+/// Rounds `size` up to the next multiple of 8, which is how the kernel lays out consecutive
+/// per-CPU value slots in a lookup/update buffer.
+fn round_up_8(size: usize) -> usize {
+    (size + 7) & !7
+}
+
+/// Returns the values associated with `key` - one for each possible CPU - or `None` if the key
+/// doesn't exist.
+pub(crate) fn bpf_map_lookup_elem_per_cpu<K: Pod, V: Pod>(
+    fd: RawFd,
+    key: &K,
+    flags: u64,
+) -> Result<Option<PerCpuValues<V>>, (i32, io::Error)> {
+    let num_cpus = nr_cpus()?;
+    let value_size = round_up_8(mem::size_of::<V>());
+    let mut buf = vec![0u8; value_size * num_cpus];
+
+    let mut attr = new_attr();
+    attr.__bindgen_anon_2.map_fd = fd as u32;
+    attr.__bindgen_anon_2.key = key as *const K as u64;
+    attr.__bindgen_anon_2.__bindgen_anon_1.value = buf.as_mut_ptr() as u64;
+    attr.__bindgen_anon_2.flags = flags;
+
+    match sys_bpf(bpf_cmd::BPF_MAP_LOOKUP_ELEM, &mut attr) {
+        Ok(_) => {
+            let values = (0..num_cpus)
+                .map(|cpu| unsafe {
+                    ptr::read_unaligned(buf.as_ptr().add(cpu * value_size) as *const V)
+                })
+                .collect();
+            Ok(Some(PerCpuValues::new(values)))
+        }
+        Err((_, io_error)) if io_error.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Inserts or updates the values associated with `key` - one for each possible CPU.
+pub(crate) fn bpf_map_update_elem_per_cpu<K: Pod, V: Pod>(
+    fd: RawFd,
+    key: &K,
+    values: &PerCpuValues<V>,
+    flags: u64,
+) -> Result<i64, (i32, io::Error)> {
+    let num_cpus = nr_cpus()?;
+    let value_size = round_up_8(mem::size_of::<V>());
+    let mut buf = vec![0u8; value_size * num_cpus];
+    for (cpu, value) in values.iter().enumerate() {
+        unsafe {
+            ptr::write_unaligned(buf.as_mut_ptr().add(cpu * value_size) as *mut V, *value);
+        }
+    }
+
+    let mut attr = new_attr();
+    attr.__bindgen_anon_2.map_fd = fd as u32;
+    attr.__bindgen_anon_2.key = key as *const K as u64;
+    attr.__bindgen_anon_2.__bindgen_anon_1.value = buf.as_mut_ptr() as u64;
+    attr.__bindgen_anon_2.flags = flags;
+
+    sys_bpf(bpf_cmd::BPF_MAP_UPDATE_ELEM, &mut attr)
+}